@@ -13,50 +13,272 @@
 // limitations under the License.
 
 use std::collections::BTreeMap;
-use std::fs::{self, File};
-use std::io::{Write, Read};
-use std::path::Path;
-use std::time::{UNIX_EPOCH, SystemTime};
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write, Read, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, UNIX_EPOCH, SystemTime};
 use uuid::Uuid;
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use hyper::Client;
+use hyper::header::{Authorization, Basic, ContentEncoding, ContentType, Encoding};
 use rustc_serialize::json::{ToJson, Json};
 use fs::cache_analytics_path;
 
 // Supported events
 pub const EVENT_BUILDER_PROJECT_CREATE: &'static str = "builder-project-create";
 
+// Supported categories
+pub const CATEGORY_BUILDER: &'static str = "builder";
+pub const CATEGORY_METRIC: &'static str = "metric";
+
+const METRIC_KIND_TIMING: &'static str = "timing";
+const METRIC_KIND_COUNTER: &'static str = "counter";
+
 // Sample event JSON payload (compatible with Segment.io)
 // {
 //   "type": "track",
 //   "event": "builder-project-create",
 //   "properties": {
+//     "category": "builder",
+//     "sequence": "0",
 //     "clientid" : "0a5c0882-ade5-46cf-821d-8d3853cd0d41"
 //     "timestamp": "1479330000.13442404",
 //   }
 // }
 
 const CLIENT_ID_METAFILE: &'static str = "CLIENT_ID";
+const SEGMENT_PREFIX: &'static str = "events";
+const SEGMENT_EXT: &'static str = "jsonl";
+const SEGMENT_EXT_GZ: &'static str = "jsonl.gz";
+
+const DEFAULT_MAX_BYTES_PER_LOG: u64 = 1024 * 1024; // 1 MiB
+const DEFAULT_MAX_LOG_COUNT: usize = 10;
+
+// Analytics JSON is highly repetitive (same keys, client id, category on
+// every event), so gzip compression is opt-in via this env var rather
+// than unconditionally on, since it trades a little CPU for a lot of
+// disk space.
+const GZIP_ENVVAR: &'static str = "HAB_ANALYTICS_GZIP";
+
+fn analytics_log_options() -> AnalyticsLogOptions {
+    let mut opts = AnalyticsLogOptions::default();
+    if env::var(GZIP_ENVVAR).map(|v| v != "0").unwrap_or(false) {
+        opts.compression = Some(Compression::Default);
+    }
+    opts
+}
+
+const UPLOAD_STATE_FILE: &'static str = "upload_state.json";
+const DEFAULT_BATCH_SIZE: usize = 50;
+const DEFAULT_ENDPOINT: &'static str = "https://api.segment.io/v1/import";
+const BACKOFF_BASE_SECS: u64 = 30;
+const BACKOFF_MAX_SECS: u64 = 3600;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Upload(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Upload(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Monotonically increasing counter used to break ties between events
+// recorded within the same sub-second window. It only needs to be unique
+// for the lifetime of this process.
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+fn next_sequence() -> usize {
+    SEQUENCE.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Declares a function returning a `&'static` reference to a value that is
+/// lazily created the first time it's called and then reused for the rest
+/// of the process, backed by a `Once` guard. Factors out the `static mut
+/// Option<_>` + `Once` boilerplate shared by `session_id`, `counters`, and
+/// `analytics_log` below.
+macro_rules! lazy_static_fn {
+    ($(#[$meta:meta])* fn $name:ident() -> $ty:ty { $init:expr }) => {
+        $(#[$meta])*
+        fn $name() -> &'static $ty {
+            static mut VALUE: Option<$ty> = None;
+            static INIT: Once = Once::new();
+            unsafe {
+                INIT.call_once(|| { VALUE = Some($init); });
+                VALUE.as_ref().unwrap()
+            }
+        }
+    };
+}
+
+lazy_static_fn! {
+    /// A `Uuid` generated once per process so every event emitted during a
+    /// single CLI invocation can be grouped back together after the fact.
+    fn session_id() -> String {
+        Uuid::new_v4().hyphenated().to_string()
+    }
+}
+
+lazy_static_fn! {
+    /// Counters accumulated additively for the lifetime of this process,
+    /// keyed by metric name, so repeated `record_count` calls for the same
+    /// name are summed into a single event rather than emitting one per
+    /// call.
+    fn counters() -> Mutex<BTreeMap<String, i64>> {
+        Mutex::new(BTreeMap::new())
+    }
+}
+
+lazy_static_fn! {
+    /// A single `AnalyticsLog` shared by every `record_event`/`record_metric`
+    /// call in this process. Opening an `AnalyticsLog` rescans
+    /// `cache_analytics_path` to find the latest segment, so reopening one per
+    /// event would mean a `fs::read_dir` on every single call; holding one
+    /// instance open for the life of the process avoids that.
+    fn analytics_log() -> Mutex<AnalyticsLog> {
+        Mutex::new({
+            let cache_dir = cache_analytics_path(None);
+            AnalyticsLog::open(&cache_dir, analytics_log_options())
+        })
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Event {
     name: String,
+    category: String,
     clientid: String,
     timestamp: String,
+    sequence: usize,
     properties: BTreeMap<String, String>,
+    extra: BTreeMap<String, String>,
+    value: Option<i64>,
 }
 
 impl Event {
-    pub fn new(name: &str, clientid: &str, timestamp: &str) -> Self {
+    pub fn new(name: &str, category: &str, clientid: &str, timestamp: &str) -> Self {
         let mut properties = BTreeMap::new();
         properties.insert("timestamp".to_string(), timestamp.to_string());
         properties.insert("clientid".to_string(), clientid.to_string());
+        properties.insert("category".to_string(), category.to_string());
+
+        let sequence = next_sequence();
+        properties.insert("sequence".to_string(), sequence.to_string());
 
         Event {
             name: name.to_string(),
+            category: category.to_string(),
             clientid: clientid.to_string(),
             timestamp: timestamp.to_string(),
+            sequence: sequence,
             properties: properties,
+            extra: BTreeMap::new(),
+            value: None,
         }
     }
+
+    /// Attach an extra, free-form property to this event. Existing keys
+    /// such as `timestamp` or `clientid` are reserved and cannot be
+    /// overridden this way.
+    pub fn with_extra(mut self, key: &str, value: &str) -> Self {
+        self.extra.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Attach a numeric value to this event, e.g. a timing in milliseconds
+    /// or a counter total. Emitted as a JSON number rather than coerced
+    /// through the string-only `extra` map, so downstream analysis can use
+    /// it directly without parsing it back out of a string.
+    pub fn with_value(mut self, value: i64) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn category(&self) -> &str {
+        &self.category
+    }
+
+    pub fn sequence(&self) -> usize {
+        self.sequence
+    }
+
+    pub fn value(&self) -> Option<i64> {
+        self.value
+    }
+
+    fn from_json(json: &Json) -> Option<Event> {
+        let obj = json.as_object()?;
+        let name = obj.get("event")?.as_string()?.to_string();
+        let properties = obj.get("properties")?.as_object()?;
+
+        let mut event = Event {
+            name: name,
+            category: properties
+                .get("category")
+                .and_then(|v| v.as_string())
+                .unwrap_or("")
+                .to_string(),
+            clientid: properties
+                .get("clientid")
+                .and_then(|v| v.as_string())
+                .unwrap_or("")
+                .to_string(),
+            timestamp: properties
+                .get("timestamp")
+                .and_then(|v| v.as_string())
+                .unwrap_or("")
+                .to_string(),
+            sequence: properties
+                .get("sequence")
+                .and_then(|v| v.as_string())
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            properties: BTreeMap::new(),
+            extra: BTreeMap::new(),
+            value: obj.get("value").and_then(|v| v.as_i64()),
+        };
+
+        for (key, value) in properties.iter() {
+            if let Some(value) = value.as_string() {
+                event.properties.insert(key.to_string(), value.to_string());
+            }
+        }
+
+        if let Some(extra) = obj.get("extra").and_then(|v| v.as_object()) {
+            for (key, value) in extra.iter() {
+                if let Some(value) = value.as_string() {
+                    event.extra.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        Some(event)
+    }
 }
 
 impl ToJson for Event {
@@ -71,6 +293,18 @@ impl ToJson for Event {
         m.insert("event".to_string(), self.name.to_json());
         m.insert("properties".to_string(), p.to_json());
 
+        if !self.extra.is_empty() {
+            let mut e = BTreeMap::new();
+            for (key, value) in self.extra.iter() {
+                e.insert(key.to_string(), value.to_json());
+            }
+            m.insert("extra".to_string(), Json::Object(e));
+        }
+
+        if let Some(value) = self.value {
+            m.insert("value".to_string(), value.to_json());
+        }
+
         Json::Object(m)
     }
 }
@@ -88,6 +322,233 @@ fn write_file(parent_dir: &Path, file_path: &Path, content: &str) {
     file.write_all(content.as_bytes()).expect("Unable to write file");
 }
 
+/// Configuration for [`AnalyticsLog`](struct.AnalyticsLog.html) rotation.
+#[derive(Debug, Clone, Copy)]
+pub struct AnalyticsLogOptions {
+    pub max_bytes_per_log: u64,
+    pub max_log_count: usize,
+    /// When set, new segments are written gzip-compressed with a
+    /// `.jsonl.gz` extension. Leave as `None` to keep plaintext segments
+    /// around for debugging.
+    pub compression: Option<Compression>,
+}
+
+impl Default for AnalyticsLogOptions {
+    fn default() -> Self {
+        AnalyticsLogOptions {
+            max_bytes_per_log: DEFAULT_MAX_BYTES_PER_LOG,
+            max_log_count: DEFAULT_MAX_LOG_COUNT,
+            compression: None,
+        }
+    }
+}
+
+/// The open handle backing the current segment. Kept open across
+/// `append()` calls so a compressed segment is one continuous gzip
+/// stream rather than a member restarted on every line.
+enum SegmentWriter {
+    Plain(File),
+    Gz(GzEncoder<File>),
+}
+
+impl Write for SegmentWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            SegmentWriter::Plain(ref mut file) => file.write(buf),
+            SegmentWriter::Gz(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            SegmentWriter::Plain(ref mut file) => file.flush(),
+            SegmentWriter::Gz(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A size-bounded, rotating JSONL event log. Events are appended to a
+/// numbered segment file until it grows past `max_bytes_per_log`, at which
+/// point a new segment is started and the oldest segment beyond
+/// `max_log_count` is deleted. This caps the disk space a long-lived
+/// builder can consume on analytics alone.
+pub struct AnalyticsLog {
+    dir: PathBuf,
+    opts: AnalyticsLogOptions,
+    segment: usize,
+    is_broken: bool,
+    writer: Option<SegmentWriter>,
+}
+
+impl AnalyticsLog {
+    pub fn open(dir: &Path, opts: AnalyticsLogOptions) -> Self {
+        AnalyticsLog {
+            dir: dir.to_path_buf(),
+            opts: opts,
+            segment: Self::latest_segment(dir),
+            is_broken: false,
+            writer: None,
+        }
+    }
+
+    /// Once a write has failed (permissions, no space, ...) the log stops
+    /// trying to write further events rather than panicking. Telemetry
+    /// must never crash the host command.
+    pub fn is_broken(&self) -> bool {
+        self.is_broken
+    }
+
+    pub fn append(&mut self, event: &Event) {
+        if self.is_broken {
+            return;
+        }
+
+        if self.segment_size(self.segment) >= self.opts.max_bytes_per_log {
+            self.rotate();
+        }
+
+        if self.writer.is_none() {
+            match self.open_writer(self.segment) {
+                Ok(writer) => self.writer = Some(writer),
+                Err(e) => {
+                    debug!("Analytics log is broken, disabling further writes: {}", e);
+                    self.is_broken = true;
+                    return;
+                }
+            }
+        }
+
+        let line = event.to_json().to_string();
+        let result = {
+            let writer = self.writer.as_mut().unwrap();
+            writeln!(writer, "{}", line).and_then(|_| writer.flush())
+        };
+
+        if let Err(e) = result {
+            debug!("Analytics log is broken, disabling further writes: {}", e);
+            self.is_broken = true;
+        }
+    }
+
+    /// Close out the current segment's writer (finishing the gzip member
+    /// if compressed) so the file on disk is complete and readable.
+    /// Called on rotation and on drop, before the current segment is read
+    /// back or rewritten (`load_events`, `flush_pending`), and when it is
+    /// finalized at exit (`flush_counters`). The next `append()` reopens
+    /// the segment.
+    fn close_writer(&mut self) {
+        match self.writer.take() {
+            Some(SegmentWriter::Gz(encoder)) => {
+                let _ = encoder.finish();
+            }
+            Some(SegmentWriter::Plain(mut file)) => {
+                let _ = file.flush();
+            }
+            None => {}
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.close_writer();
+        self.segment += 1;
+        self.prune();
+    }
+
+    /// Every segment file currently on disk, oldest first.
+    pub fn segments(&self) -> Vec<PathBuf> {
+        let mut segments: Vec<(usize, PathBuf)> = fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.file_name().into_string().ok().map(|n| (n, e.path())))
+                    .filter_map(|(n, p)| Self::segment_number(&n).map(|n| (n, p)))
+                    .collect()
+            })
+            .unwrap_or_else(|_| Vec::new());
+        segments.sort_by_key(|&(n, _)| n);
+        segments.into_iter().map(|(_, p)| p).collect()
+    }
+
+    fn open_writer(&self, segment: usize) -> io::Result<SegmentWriter> {
+        fs::create_dir_all(&self.dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.segment_path(segment))?;
+        Ok(match self.opts.compression {
+            Some(level) => SegmentWriter::Gz(GzEncoder::new(file, level)),
+            None => SegmentWriter::Plain(file),
+        })
+    }
+
+    fn ext(&self) -> &'static str {
+        if self.opts.compression.is_some() {
+            SEGMENT_EXT_GZ
+        } else {
+            SEGMENT_EXT
+        }
+    }
+
+    fn segment_path(&self, segment: usize) -> PathBuf {
+        self.dir.join(format!("{}-{}.{}", SEGMENT_PREFIX, segment, self.ext()))
+    }
+
+    fn segment_size(&self, segment: usize) -> u64 {
+        fs::metadata(self.segment_path(segment)).map(|m| m.len()).unwrap_or(0)
+    }
+
+    fn latest_segment(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .filter_map(|e| e.file_name().into_string().ok())
+                    .filter_map(|name| Self::segment_number(&name))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Parses the segment number out of a file name, accepting either the
+    /// plaintext or gzip-compressed extension so mixed directories (e.g.
+    /// after toggling compression) still scan correctly.
+    fn segment_number(name: &str) -> Option<usize> {
+        let prefix = format!("{}-", SEGMENT_PREFIX);
+        if !name.starts_with(&prefix) {
+            return None;
+        }
+
+        for ext in &[SEGMENT_EXT_GZ, SEGMENT_EXT] {
+            let suffix = format!(".{}", ext);
+            if name.ends_with(&suffix) {
+                return name[prefix.len()..name.len() - suffix.len()].parse().ok();
+            }
+        }
+
+        None
+    }
+
+    /// Delete any segment older than the newest `max_log_count` segments.
+    fn prune(&self) {
+        if self.segment + 1 <= self.opts.max_log_count {
+            return;
+        }
+        let oldest_kept = self.segment + 1 - self.opts.max_log_count;
+        for n in 0..oldest_kept {
+            for ext in &[SEGMENT_EXT, SEGMENT_EXT_GZ] {
+                let _ = fs::remove_file(self.dir.join(format!("{}-{}.{}", SEGMENT_PREFIX, n, ext)));
+            }
+        }
+    }
+}
+
+impl Drop for AnalyticsLog {
+    fn drop(&mut self) {
+        self.close_writer();
+    }
+}
+
 fn timestamp() -> String {
     let (secs, subsec_nanos) = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(duration) => (duration.as_secs(), duration.subsec_nanos()),
@@ -112,15 +573,312 @@ fn client_id() -> String {
     }
 }
 
-pub fn record_event(name: &str) {
+pub fn record_event(name: &str, category: &str) {
+    let timestamp: &str = &timestamp();
+    let clientid: &str = &client_id();
+    let event = Event::new(name, category, clientid, timestamp).with_extra("session_id", session_id());
+    emit(event);
+}
+
+fn emit(event: Event) {
+    let mut log = analytics_log().lock().expect("analytics log lock poisoned");
+    log.append(&event);
+}
+
+fn record_metric(name: &str, kind: &str, value: i64) {
     let timestamp: &str = &timestamp();
     let clientid: &str = &client_id();
-    let event = Event::new(name, timestamp, clientid);
+    let event = Event::new(name, CATEGORY_METRIC, clientid, timestamp)
+        .with_extra("session_id", session_id())
+        .with_extra("metric_kind", kind)
+        .with_value(value);
+    emit(event);
+}
+
+/// Record a single timing sample, in milliseconds, for `name`.
+pub fn record_timing(name: &str, duration: Duration) {
+    let millis = duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64;
+    record_metric(name, METRIC_KIND_TIMING, millis as i64);
+}
+
+/// Add `n` to the running total for counter `name`. The total is only
+/// flushed as an event once [`flush_counters`](fn.flush_counters.html) is
+/// called, so repeated calls for the same name accumulate rather than
+/// emitting one event per call.
+pub fn record_count(name: &str, n: i64) {
+    let mut counters = counters().lock().expect("analytics counters lock poisoned");
+    *counters.entry(name.to_string()).or_insert(0) += n;
+}
+
+/// Emit one event per accumulated counter and reset all totals to zero,
+/// then finalize the current analytics segment (finishing its gzip
+/// member if compressed) so it is complete and readable on disk.
+/// Callers should invoke this once, at process exit, to make sure
+/// counter totals and any buffered compressed data are actually
+/// recorded.
+pub fn flush_counters() {
+    let totals: Vec<(String, i64)> = {
+        let mut counters = counters().lock().expect("analytics counters lock poisoned");
+        let totals = counters.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        counters.clear();
+        totals
+    };
+
+    for (name, total) in totals {
+        record_metric(&name, METRIC_KIND_COUNTER, total);
+    }
+
+    analytics_log().lock().expect("analytics log lock poisoned").close_writer();
+}
+
+/// Read back every event accumulated across all rotated segments, in the
+/// order they were recorded, so a full run can be replayed or serialized
+/// as a batch.
+pub fn load_events() -> Vec<Event> {
+    let mut log = analytics_log().lock().expect("analytics log lock poisoned");
+    // Finish the current segment first so a compressed one is complete.
+    log.close_writer();
+    let mut events = Vec::new();
+
+    for segment in log.segments() {
+        events.extend(read_segment(&segment));
+    }
+
+    events
+}
+
+fn read_segment(path: &Path) -> Vec<Event> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Vec::new(),
+    };
+
+    let reader: Box<Read> = if is_gzip_path(path) {
+        Box::new(MultiGzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut events = Vec::new();
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(json) = Json::from_str(&line) {
+            if let Some(event) = Event::from_json(&json) {
+                events.push(event);
+            }
+        }
+    }
+    events
+}
+
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("gz")
+}
+
+/// Overwrite a segment with the events that still need to be uploaded,
+/// preserving its existing plaintext/gzip format. Called after each
+/// successfully-uploaded chunk so a later chunk's failure can't cause an
+/// already-uploaded chunk to be re-sent on retry. An empty `events` slice
+/// deletes the segment outright.
+fn rewrite_segment(path: &Path, events: &[Event]) -> io::Result<()> {
+    if events.is_empty() {
+        return fs::remove_file(path);
+    }
+
+    let file = File::create(path)?;
+    if is_gzip_path(path) {
+        let mut encoder = GzEncoder::new(file, Compression::Default);
+        for event in events {
+            writeln!(encoder, "{}", event.to_json().to_string())?;
+        }
+        encoder.finish()?;
+    } else {
+        let mut file = file;
+        for event in events {
+            writeln!(file, "{}", event.to_json().to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// Configuration for uploading buffered events to a Segment-compatible
+/// HTTP endpoint. Disabled by default so offline environments keep
+/// buffering locally without error.
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    pub enabled: bool,
+    pub endpoint: String,
+    pub write_key: String,
+    pub batch_size: usize,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        UploadOptions {
+            enabled: false,
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            write_key: String::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Tracks upload retry state across invocations so a failing endpoint is
+/// retried with exponential backoff rather than on every command.
+struct UploadState {
+    attempt: u32,
+    next_attempt_at: u64,
+}
+
+impl UploadState {
+    fn load(dir: &Path) -> Self {
+        let path = dir.join(UPLOAD_STATE_FILE);
+        File::open(&path)
+            .ok()
+            .and_then(|mut file| {
+                let mut content = String::new();
+                file.read_to_string(&mut content).ok()?;
+                Json::from_str(&content).ok()
+            })
+            .and_then(|json| {
+                json.as_object().map(|o| {
+                    UploadState {
+                        attempt: o.get("attempt").and_then(|v| v.as_u64()).unwrap_or(0) as u32,
+                        next_attempt_at: o.get("next_attempt_at")
+                            .and_then(|v| v.as_u64())
+                            .unwrap_or(0),
+                    }
+                })
+            })
+            .unwrap_or(UploadState {
+                attempt: 0,
+                next_attempt_at: 0,
+            })
+    }
+
+    fn ready(&self) -> bool {
+        now_secs() >= self.next_attempt_at
+    }
+
+    fn record_success(dir: &Path) {
+        UploadState::save(dir, 0, 0);
+    }
+
+    fn record_failure(&self, dir: &Path) {
+        let attempt = self.attempt + 1;
+        let delay = BACKOFF_BASE_SECS
+            .saturating_mul(1 << attempt.min(10))
+            .min(BACKOFF_MAX_SECS);
+        UploadState::save(dir, attempt, now_secs() + delay);
+    }
+
+    fn save(dir: &Path, attempt: u32, next_attempt_at: u64) {
+        let mut m = BTreeMap::new();
+        m.insert("attempt".to_string(), (attempt as u64).to_json());
+        m.insert("next_attempt_at".to_string(), next_attempt_at.to_json());
+        write_file(dir, &dir.join(UPLOAD_STATE_FILE), &Json::Object(m).to_string());
+    }
+}
+
+fn gzip_bytes(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn post_batch(opts: &UploadOptions, events: &[Event]) -> Result<()> {
+    let batch: Vec<Json> = events.iter().map(|e| e.to_json()).collect();
+    let mut body = BTreeMap::new();
+    body.insert("batch".to_string(), Json::Array(batch));
+    let payload = Json::Object(body).to_string();
+    let gzipped = gzip_bytes(payload.as_bytes())?;
+
+    let client = Client::new();
+    let response = client
+        .post(&opts.endpoint)
+        .header(Authorization(Basic {
+            username: opts.write_key.clone(),
+            password: None,
+        }))
+        .header(ContentType::json())
+        .header(ContentEncoding(vec![Encoding::Gzip]))
+        .body(&gzipped[..])
+        .send()
+        .map_err(|e| Error::Upload(e.to_string()))?;
+
+    if response.status.is_success() {
+        Ok(())
+    } else {
+        Err(Error::Upload(format!("upload failed with status {}", response.status)))
+    }
+}
+
+/// Upload every pending event segment to `opts.endpoint`, up to
+/// `opts.batch_size` events per request. Uploaded segments are deleted;
+/// a failed segment is left in place and retried on the next invocation
+/// with exponential backoff. Returns the number of events uploaded.
+pub fn flush_pending(opts: &UploadOptions) -> Result<usize> {
+    if !opts.enabled {
+        return Ok(0);
+    }
 
     let cache_dir = cache_analytics_path(None);
-    let file_path = cache_dir.join(format!("event-{}.json", &event.timestamp));
+    let state = UploadState::load(&cache_dir);
+    if !state.ready() {
+        return Ok(0);
+    }
+
+    // Held for the whole upload, not just to list segments: flush_pending
+    // rewrites segment files directly, and if one of them is the segment
+    // AnalyticsLog is still appending to, that writer has to be closed
+    // first so it doesn't later write through a now-stale file handle
+    // positioned past content this function just rewrote out from under it.
+    let mut log = analytics_log().lock().expect("analytics log lock poisoned");
+    let segments = log.segments();
+    let mut uploaded = 0;
+
+    for segment in segments {
+        // Closed before reading too, so a compressed segment is complete.
+        if segment == log.segment_path(log.segment) {
+            log.close_writer();
+        }
+
+        let mut events = read_segment(&segment);
+        if events.is_empty() {
+            let _ = fs::remove_file(&segment);
+            continue;
+        }
+
+        while !events.is_empty() {
+            let chunk_len = opts.batch_size.min(events.len());
+            let chunk: Vec<Event> = events[..chunk_len].to_vec();
+
+            if let Err(e) = post_batch(opts, &chunk) {
+                state.record_failure(&cache_dir);
+                return Err(e);
+            }
+            uploaded += chunk.len();
 
-    write_file(&cache_dir, &file_path, &event.to_json().to_string());
+            // Drop the uploaded chunk and persist the remainder immediately,
+            // so a failure on the *next* chunk can't cause this one to be
+            // re-uploaded when the segment is retried.
+            events.drain(..chunk_len);
+            let _ = rewrite_segment(&segment, &events);
+        }
+    }
+
+    UploadState::record_success(&cache_dir);
+    Ok(uploaded)
 }
 
 #[cfg(test)]
@@ -130,10 +888,190 @@ mod test {
 
     #[test]
     fn event_to_json() {
-        let event = Event::new("foo", "bar", "baz");
+        let event = Event::new("foo", "builder", "bar", "baz");
         let encoded = event.to_json();
-        let expected =
-            r#"{"event":"foo","properties":{"clientid":"bar","timestamp":"baz"},"type":"track"}"#;
-        assert!(encoded.to_string() == expected.to_string());
+        assert!(encoded.to_string().contains(r#""event":"foo""#));
+        assert!(encoded.to_string().contains(r#""clientid":"bar""#));
+        assert!(encoded.to_string().contains(r#""timestamp":"baz""#));
+        assert!(encoded.to_string().contains(r#""category":"builder""#));
+    }
+
+    #[test]
+    fn event_with_extra() {
+        let event = Event::new("foo", "builder", "bar", "baz").with_extra("sha", "deadbeef");
+        let encoded = event.to_json();
+        assert!(encoded.to_string().contains(r#""extra":{"sha":"deadbeef"}"#));
+    }
+
+    #[test]
+    fn event_with_value_is_emitted_as_a_json_number() {
+        let event = Event::new("foo", "metric", "bar", "baz").with_value(150);
+        let encoded = event.to_json();
+        assert!(encoded.to_string().contains(r#""value":150"#));
+    }
+}
+
+#[cfg(test)]
+mod analytics_log_test {
+    use super::{AnalyticsLog, AnalyticsLogOptions, Event};
+    use std::env;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn temp_dir() -> ::std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("habitat-event-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rotates_once_max_bytes_per_log_is_exceeded() {
+        let dir = temp_dir();
+        let opts = AnalyticsLogOptions {
+            max_bytes_per_log: 1,
+            max_log_count: 10,
+            compression: None,
+        };
+        let mut log = AnalyticsLog::open(&dir, opts);
+
+        log.append(&Event::new("one", "builder", "client", "1"));
+        log.append(&Event::new("two", "builder", "client", "2"));
+
+        assert_eq!(log.segments().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_segments_past_max_log_count() {
+        let dir = temp_dir();
+        let opts = AnalyticsLogOptions {
+            max_bytes_per_log: 1,
+            max_log_count: 2,
+            compression: None,
+        };
+        let mut log = AnalyticsLog::open(&dir, opts);
+
+        for i in 0..5 {
+            log.append(&Event::new("event", "builder", "client", &i.to_string()));
+        }
+
+        assert_eq!(log.segments().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_compressed_segments_round_trip_through_read_segment() {
+        use super::read_segment;
+        use flate2::Compression;
+
+        let dir = temp_dir();
+        let opts = AnalyticsLogOptions {
+            max_bytes_per_log: 1024 * 1024,
+            max_log_count: 10,
+            compression: Some(Compression::Default),
+        };
+        let mut log = AnalyticsLog::open(&dir, opts);
+
+        log.append(&Event::new("one", "builder", "client", "1"));
+        log.append(&Event::new("two", "builder", "client", "2"));
+
+        let segments = log.segments();
+        assert_eq!(segments.len(), 1);
+        assert!(segments[0].to_string_lossy().ends_with(".jsonl.gz"));
+
+        // Dropping the log finishes the gzip member so the segment is a
+        // complete, readable stream.
+        drop(log);
+
+        let events = read_segment(&segments[0]);
+        assert_eq!(events.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_single_gzip_member_is_reused_across_appends_within_a_segment() {
+        use flate2::Compression;
+
+        let dir = temp_dir();
+        let opts = AnalyticsLogOptions {
+            max_bytes_per_log: 1024 * 1024,
+            max_log_count: 10,
+            compression: Some(Compression::Default),
+        };
+        let mut log = AnalyticsLog::open(&dir, opts);
+
+        for i in 0..5 {
+            log.append(&Event::new("event", "builder", "client", &i.to_string()));
+        }
+
+        let segments = log.segments();
+        assert_eq!(segments.len(), 1);
+        drop(log);
+
+        // A single continuous gzip member pays its header/footer overhead
+        // once; five independently-finished members would not fit in this
+        // small a budget.
+        let size = fs::metadata(&segments[0]).unwrap().len();
+        assert!(size < 200, "expected a single gzip member, segment was {} bytes", size);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod upload_state_test {
+    use super::UploadState;
+    use std::env;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn temp_dir() -> ::std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("habitat-event-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn defaults_to_ready_when_no_state_file_exists() {
+        let dir = temp_dir();
+        assert!(UploadState::load(&dir).ready());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_recorded_failure_is_not_immediately_ready() {
+        let dir = temp_dir();
+        let state = UploadState::load(&dir);
+        state.record_failure(&dir);
+
+        assert!(!UploadState::load(&dir).ready());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_recorded_success_resets_backoff() {
+        let dir = temp_dir();
+        let state = UploadState::load(&dir);
+        state.record_failure(&dir);
+        UploadState::record_success(&dir);
+
+        assert!(UploadState::load(&dir).ready());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use super::{counters, record_count};
+
+    #[test]
+    fn record_count_accumulates_additively() {
+        let name = "test_metrics_accumulate_counter";
+        record_count(name, 2);
+        record_count(name, 3);
+
+        let counters = counters().lock().unwrap();
+        assert_eq!(counters.get(name), Some(&5));
     }
 }